@@ -19,6 +19,77 @@ fn main() {
                 .required(true)
                 .index(2),
         )
+        .arg(
+            Arg::new("archive")
+                .short('a')
+                .long("archive")
+//                .about("Preserves permissions, timestamps, ownership, symlinks and xattrs")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("exclude")
+                .long("exclude")
+//                .about("Skips paths matching this glob; may be given multiple times")
+                .takes_value(true)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            Arg::new("include")
+                .long("include")
+//                .about("Re-includes paths matching this glob, overriding an earlier --exclude that also matches")
+                .takes_value(true)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            Arg::new("respect-gitignore")
+                .long("respect-gitignore")
+//                .about("Skips paths ignored by any .gitignore found while scanning")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("update")
+                .long("update")
+//                .about("Skips a file whose destination already matches it in size and mtime")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("checksum")
+                .long("checksum")
+//                .about("With --update, compares file contents instead of size/mtime")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("archive-format")
+                .long("archive-format")
+//                .about("Forces packing into a tar/tar.gz instead of auto-detecting from the dest extension")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("rename")
+                .long("rename")
+//                .about("Rewrites the relative path with a <from>:<to> rule; may be given multiple times")
+                .takes_value(true)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            Arg::new("regex")
+                .long("regex")
+//                .about("Treats --rename patterns as regexes with $1-style capture groups instead of */? globs")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("threads")
+                .short('j')
+                .long("threads")
+//                .about("Overrides the worker count; defaults to the CPU count and rejects 0")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("max-queued")
+                .long("max-queued")
+//                .about("Once this many entries are pending, a worker processes queued entries before scanning another directory")
+                .takes_value(true),
+        )
         .get_matches();
 
     let sources: Vec<PathBuf> = matches
@@ -30,29 +101,120 @@ fn main() {
 
     let dest = PathBuf::from_str(matches.value_of("dest").unwrap()).unwrap();
 
-    lib::main(sources, dest);
+    let archive = matches.is_present("archive");
+
+    // indices_of recovers the true argv order across --exclude/--include,
+    // which clap's two separate Vecs would otherwise lose.
+    let mut pattern_rule_specs: Vec<(usize, lib::PatternRuleKind, String)> = vec![];
+    if let (Some(values), Some(indices)) = (matches.values_of("exclude"), matches.indices_of("exclude")) {
+        for (index, value) in indices.zip(values) {
+            pattern_rule_specs.push((index, lib::PatternRuleKind::Exclude, value.to_string()));
+        }
+    }
+    if let (Some(values), Some(indices)) = (matches.values_of("include"), matches.indices_of("include")) {
+        for (index, value) in indices.zip(values) {
+            pattern_rule_specs.push((index, lib::PatternRuleKind::Include, value.to_string()));
+        }
+    }
+    pattern_rule_specs.sort_by_key(|(index, _, _)| *index);
+    let pattern_rule_specs: Vec<(lib::PatternRuleKind, String)> = pattern_rule_specs
+        .into_iter()
+        .map(|(_, kind, pattern)| (kind, pattern))
+        .collect();
+
+    let respect_gitignore = matches.is_present("respect-gitignore");
+
+    let update = matches.is_present("update");
+    let checksum = matches.is_present("checksum");
+
+    let archive_format = matches.value_of("archive-format").map(String::from);
+
+    let rename_specs: Vec<String> = matches
+        .values_of("rename")
+        .map(|values| values.map(String::from).collect())
+        .unwrap_or_default();
+
+    let use_regex = matches.is_present("regex");
+
+    let threads: Option<usize> = matches
+        .value_of("threads")
+        .map(|value| value.parse().expect("--threads expects a number"));
+
+    let max_queued: Option<usize> = matches.value_of("max-queued").map(|value| {
+        let limit: usize = value.parse().expect("--max-queued expects a number");
+        if limit == 0 {
+            panic!("--max-queued must be at least 1");
+        }
+        limit
+    });
+
+    let options = lib::Options {
+        archive,
+        pattern_rule_specs,
+        respect_gitignore,
+        update,
+        checksum,
+        archive_format,
+        rename_specs,
+        use_regex,
+        threads,
+        max_queued,
+    };
+
+    lib::main(sources, dest, options);
 }
 
 mod lib {
+    use crossbeam_deque::{Injector, Steal, Stealer, Worker as Deque};
     use crossterm::{cursor, execute, style, terminal};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
     use num_cpus;
     use std::collections::HashMap;
     use std::fs;
     use std::io::{stdout, Stdout, Write};
     use std::path::PathBuf;
     use std::process;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::sync::mpsc;
     use std::sync::{Arc, Mutex};
     use std::thread;
 
+    use self::filter::{IgnoreStack, PatternRules};
+    use self::rename::RenameRule;
+
     use log::{error, LevelFilter};
+    use sha2::{Digest, Sha256};
     use syslog::{BasicLogger, Facility, Formatter3164};
 
+    pub struct Options {
+        pub archive: bool,
+        pub pattern_rule_specs: Vec<(PatternRuleKind, String)>,
+        pub respect_gitignore: bool,
+        pub update: bool,
+        pub checksum: bool,
+        pub archive_format: Option<String>,
+        pub rename_specs: Vec<String>,
+        pub use_regex: bool,
+        pub threads: Option<usize>,
+        pub max_queued: Option<usize>,
+    }
+
+    // Carried alongside each --exclude/--include pattern in command-line
+    // order so the last matching rule, by position, wins.
+    #[derive(Clone, Copy)]
+    pub enum PatternRuleKind {
+        Include,
+        Exclude,
+    }
+
     #[derive(Debug, PartialEq, Eq)]
     pub enum Task {
         Initalizing,
         Idle,
         Scanning(PathBuf),
         Coping(PathBuf),
+        Archiving(PathBuf),
     }
     impl Clone for Task {
         fn clone(&self) -> Task {
@@ -61,14 +223,15 @@ mod lib {
                 Task::Initalizing => Task::Initalizing,
                 Task::Coping(file) => Task::Coping(file.to_path_buf()),
                 Task::Scanning(dir) => Task::Scanning(dir.to_path_buf()),
+                Task::Archiving(file) => Task::Archiving(file.to_path_buf()),
             }
         }
     }
 
-    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
     pub enum Entry {
-        File(PathBuf, PathBuf), // source, dest
-        Dir(PathBuf, PathBuf),  // source, dest
+        File(PathBuf, PathBuf),    // source root, source path
+        Dir(PathBuf, PathBuf, IgnoreStack), // source root, source path, inherited .gitignore stack
+        Symlink(PathBuf, PathBuf), // source root, source path
     }
 
     pub struct Worker {
@@ -78,30 +241,422 @@ mod lib {
     pub struct State {
         pub sources: Mutex<Vec<PathBuf>>,
         pub dest: Mutex<PathBuf>,
-        pub entries: Mutex<Vec<Entry>>,
+        // Seed queue for the initial set of entries; workers steal from this
+        // once and otherwise feed each other through their own deques.
+        pub injector: Injector<Entry>,
+        // Entries that have been queued (in the injector or any deque) but not
+        // yet taken by a worker. Reaching zero while active_workers is also
+        // zero, after a local/steal attempt failed, is what lets a worker
+        // decide there is nothing left to do.
+        pub pending_entries: AtomicUsize,
+        pub active_workers: AtomicUsize,
         pub next_id: Mutex<u16>,
         pub workers: Mutex<HashMap<u16, Worker>>,
         pub stdout: Mutex<Stdout>,
         // pub logger: Mutex<Logger<LoggerBackend, Formatter3164>>,
         // pub stderror: Mutex<fs::File>,
         pub entries_processed: Mutex<u64>,
+        pub archive: bool,
+        // Packing into a tar(.gz) also needs symlinks enumerated, independent of -a.
+        pub tar_output: bool,
+        pub pattern_rules: Option<PatternRules>,
+        pub respect_gitignore: bool,
+        // Keyed by SHA-256, so later copies of identical content hard-link
+        // to the first destination instead of recopying.
+        pub hashes: Mutex<HashMap<[u8; 32], PathBuf>>,
+        pub update: bool,
+        pub checksum: bool,
+        pub bytes_saved: AtomicU64,
+        pub files_skipped: AtomicU64,
+        pub rename_rules: Vec<RenameRule>,
+        // Keyed by renamed destination, so a second source colliding onto
+        // the same name is caught instead of silently overwritten.
+        pub dest_claims: Mutex<HashMap<PathBuf, PathBuf>>,
+        pub max_queued: Option<usize>,
+    }
+
+    #[derive(Clone, Copy)]
+    pub enum ArchiveFormat {
+        Tar,
+        TarGz,
+    }
+
+    impl ArchiveFormat {
+        pub fn detect(dest: &PathBuf, forced: &Option<String>) -> Option<ArchiveFormat> {
+            if let Some(forced) = forced {
+                return Some(match forced.as_str() {
+                    "tar" => ArchiveFormat::Tar,
+                    "tar.gz" | "tgz" => ArchiveFormat::TarGz,
+                    other => panic!("Unknown --archive-format '{}'; expected tar or tar.gz", other),
+                });
+            }
+            let name = dest.to_str()?;
+            if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+                Some(ArchiveFormat::TarGz)
+            } else if name.ends_with(".tar") {
+                Some(ArchiveFormat::Tar)
+            } else {
+                None
+            }
+        }
+    }
+
+    pub enum TarJob {
+        Dir { disk_path: PathBuf, name: PathBuf },
+        File { disk_path: PathBuf, name: PathBuf },
+        Symlink { disk_path: PathBuf, name: PathBuf },
+    }
+
+    // Only this thread touches the `tar::Builder`; workers still enumerate
+    // concurrently and just send finished entries over the channel.
+    mod tar_archive {
+        use super::{send_to_error, ArchiveFormat, State, TarJob};
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::fs;
+        use std::io::Write;
+        use std::sync::mpsc::Receiver;
+        use std::sync::Arc;
+
+        pub fn run(dest: &std::path::Path, format: ArchiveFormat, rx: Receiver<TarJob>, state: Arc<State>) {
+            let file = match fs::File::create(dest) {
+                Ok(file) => file,
+                Err(error) => {
+                    send_to_error(state.clone(), error.to_string());
+                    return;
+                }
+            };
+            match format {
+                ArchiveFormat::Tar => {
+                    let builder = write_jobs(tar::Builder::new(file), rx, &state);
+                    if let Ok(mut file) = builder.into_inner() {
+                        let _ = file.flush();
+                    }
+                }
+                ArchiveFormat::TarGz => {
+                    let encoder = GzEncoder::new(file, Compression::default());
+                    let builder = write_jobs(tar::Builder::new(encoder), rx, &state);
+                    if let Ok(encoder) = builder.into_inner() {
+                        let _ = encoder.finish();
+                    }
+                }
+            }
+        }
+
+        fn write_jobs<W: Write>(
+            mut builder: tar::Builder<W>,
+            rx: Receiver<TarJob>,
+            state: &Arc<State>,
+        ) -> tar::Builder<W> {
+            for job in rx {
+                let result = match &job {
+                    TarJob::Dir { disk_path, name } => builder.append_dir(name, disk_path),
+                    TarJob::File { disk_path, name } => builder.append_path_with_name(disk_path, name),
+                    TarJob::Symlink { disk_path, name } => append_symlink(&mut builder, disk_path, name),
+                };
+                if let Err(error) = result {
+                    send_to_error(state.clone(), error.to_string());
+                }
+            }
+            builder
+        }
+
+        fn append_symlink<W: Write>(
+            builder: &mut tar::Builder<W>,
+            disk_path: &std::path::Path,
+            name: &std::path::Path,
+        ) -> std::io::Result<()> {
+            let target = fs::read_link(disk_path)?;
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            header.set_mode(0o777);
+            header.set_cksum();
+            builder.append_link(&mut header, name, target)
+        }
+    }
+
+    mod filter {
+        use globset::{Error, Glob, GlobSet, GlobSetBuilder};
+        use super::PatternRuleKind;
+        use ignore::gitignore::{Gitignore, GitignoreBuilder};
+        use std::path::Path;
+        use std::sync::Arc;
+
+        pub struct PatternRules {
+            globs: GlobSet,
+            rules: Vec<PatternRuleKind>,
+        }
+
+        impl PatternRules {
+            pub fn build(patterns: &[(PatternRuleKind, String)]) -> Result<Option<PatternRules>, Error> {
+                if patterns.is_empty() {
+                    return Ok(None);
+                }
+                let mut builder = GlobSetBuilder::new();
+                let mut rules = vec![];
+                for (kind, pattern) in patterns {
+                    builder.add(Glob::new(pattern)?);
+                    rules.push(*kind);
+                }
+                Ok(Some(PatternRules {
+                    globs: builder.build()?,
+                    rules,
+                }))
+            }
+
+            pub fn is_excluded(&self, relative: &Path) -> bool {
+                match self.globs.matches(relative).iter().max() {
+                    Some(&index) => matches!(self.rules[index], PatternRuleKind::Exclude),
+                    None => false,
+                }
+            }
+        }
+
+        #[derive(Clone)]
+        pub struct IgnoreStack(Arc<Vec<Gitignore>>);
+
+        impl IgnoreStack {
+            pub fn root() -> IgnoreStack {
+                IgnoreStack(Arc::new(vec![]))
+            }
+
+            // Stacks `dir`'s own .gitignore on top, so it outranks its ancestors.
+            pub fn push(&self, dir: &Path) -> IgnoreStack {
+                let gitignore_path = dir.join(".gitignore");
+                if !gitignore_path.is_file() {
+                    return self.clone();
+                }
+                let mut builder = GitignoreBuilder::new(dir);
+                if builder.add(&gitignore_path).is_some() {
+                    return self.clone();
+                }
+                match builder.build() {
+                    Ok(gitignore) => {
+                        let mut layers = (*self.0).clone();
+                        layers.push(gitignore);
+                        IgnoreStack(Arc::new(layers))
+                    }
+                    Err(_) => self.clone(),
+                }
+            }
+
+            // Innermost (last-pushed) layer with an opinion wins.
+            pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+                self.0
+                    .iter()
+                    .rev()
+                    .find_map(|gitignore| {
+                        let matched = gitignore.matched(path, is_dir);
+                        if matched.is_ignore() {
+                            Some(true)
+                        } else if matched.is_whitelist() {
+                            Some(false)
+                        } else {
+                            None
+                        }
+                    })
+                    .unwrap_or(false)
+            }
+        }
+    }
+
+    // Glob mode translates `*`/`?` into capturing groups so `$1`-style
+    // substitutions still work; --regex mode uses `from` as-is.
+    mod rename {
+        use regex::Regex;
+        use std::path::{Path, PathBuf};
+
+        pub struct RenameRule {
+            pattern: Regex,
+            replacement: String,
+        }
+
+        impl RenameRule {
+            // Splits on the first ':', since `to` may itself contain one.
+            pub fn parse(spec: &str, use_regex: bool) -> Result<RenameRule, regex::Error> {
+                let (from, to) = spec
+                    .split_once(':')
+                    .unwrap_or_else(|| panic!("--rename expects <from>:<to>, got '{}'", spec));
+                let pattern = if use_regex {
+                    Regex::new(from)?
+                } else {
+                    Regex::new(&glob_to_regex(from))?
+                };
+                Ok(RenameRule {
+                    pattern,
+                    replacement: to.to_string(),
+                })
+            }
+        }
+
+        pub fn apply(rules: &[RenameRule], relative: &Path) -> PathBuf {
+            let mut current = relative.to_string_lossy().into_owned();
+            for rule in rules {
+                current = rule.pattern.replace(&current, rule.replacement.as_str()).into_owned();
+            }
+            PathBuf::from(current)
+        }
+
+        // Anchors the path and turns `*`/`?` into capturing groups.
+        fn glob_to_regex(glob: &str) -> String {
+            let mut regex = String::from("^");
+            for ch in glob.chars() {
+                match ch {
+                    '*' => regex.push_str("(.*)"),
+                    '?' => regex.push_str("(.)"),
+                    '.' | '+' | '(' | ')' | '|' | '^' | '$' | '[' | ']' | '{' | '}' | '\\' => {
+                        regex.push('\\');
+                        regex.push(ch);
+                    }
+                    other => regex.push(other),
+                }
+            }
+            regex.push('$');
+            regex
+        }
+    }
+
+    #[cfg(unix)]
+    mod archive {
+        use super::{send_to_error, State};
+        use nix::sys::stat::{utimensat, UtimensatFlags};
+        use nix::sys::time::TimeSpec;
+        use nix::unistd::{fchownat, FchownatFlags, Gid, Uid};
+        use std::fs;
+        use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+        use std::path::Path;
+        use std::sync::Arc;
+
+        pub fn preserve_metadata(src: &Path, dest: &Path, state: Arc<State>) {
+            let metadata = match fs::symlink_metadata(src) {
+                Ok(metadata) => metadata,
+                Err(error) => {
+                    send_to_error(state.clone(), error.to_string());
+                    return;
+                }
+            };
+            let is_symlink = metadata.file_type().is_symlink();
+
+            if !is_symlink {
+                if let Err(error) =
+                    fs::set_permissions(dest, fs::Permissions::from_mode(metadata.mode()))
+                {
+                    send_to_error(state.clone(), error.to_string());
+                }
+            }
+
+            let atime = TimeSpec::new(metadata.atime(), metadata.atime_nsec());
+            let mtime = TimeSpec::new(metadata.mtime(), metadata.mtime_nsec());
+            let follow = if is_symlink {
+                UtimensatFlags::NoFollowSymlink
+            } else {
+                UtimensatFlags::FollowSymlink
+            };
+            if let Err(error) = utimensat(None, dest, &atime, &mtime, follow) {
+                send_to_error(state.clone(), error.to_string());
+            }
+
+            // Best-effort: only root can chown arbitrary uid/gid, so an
+            // EPERM here is expected and silently tolerated.
+            let _ = fchownat(
+                None,
+                dest,
+                Some(Uid::from_raw(metadata.uid())),
+                Some(Gid::from_raw(metadata.gid())),
+                if is_symlink {
+                    FchownatFlags::NoFollowSymlink
+                } else {
+                    FchownatFlags::FollowSymlink
+                },
+            );
+
+            if !is_symlink {
+                copy_xattrs(src, dest, state);
+            }
+        }
+
+        fn copy_xattrs(src: &Path, dest: &Path, state: Arc<State>) {
+            let names = match xattr::list(src) {
+                Ok(names) => names,
+                Err(_) => return, // filesystem doesn't support xattrs
+            };
+            for name in names {
+                match xattr::get(src, &name) {
+                    Ok(Some(value)) => {
+                        if let Err(error) = xattr::set(dest, &name, &value) {
+                            send_to_error(state.clone(), error.to_string());
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(error) => send_to_error(state.clone(), error.to_string()),
+                }
+            }
+        }
+
+        pub fn recreate_symlink(src: &Path, dest: &Path, state: Arc<State>) {
+            match fs::read_link(src) {
+                Ok(target) => {
+                    if let Err(error) = symlink(&target, dest) {
+                        send_to_error(state.clone(), error.to_string());
+                    }
+                }
+                Err(error) => send_to_error(state.clone(), error.to_string()),
+            }
+        }
+
+        // Stamps `dest` with `src`'s mtime so --update without --archive has
+        // something to compare against on the next run.
+        pub fn copy_mtime(src: &Path, dest: &Path, state: Arc<State>) {
+            let metadata = match fs::symlink_metadata(src) {
+                Ok(metadata) => metadata,
+                Err(error) => {
+                    send_to_error(state.clone(), error.to_string());
+                    return;
+                }
+            };
+            let atime = TimeSpec::new(metadata.atime(), metadata.atime_nsec());
+            let mtime = TimeSpec::new(metadata.mtime(), metadata.mtime_nsec());
+            if let Err(error) = utimensat(None, dest, &atime, &mtime, UtimensatFlags::FollowSymlink) {
+                send_to_error(state.clone(), error.to_string());
+            }
+        }
     }
 
     pub fn send_to_error(_state: Arc<State>, msg: String) {
         error!("{}", msg);
     }
 
-    pub fn read_dir(src: &PathBuf, dir: &PathBuf, state: Arc<State>) -> Vec<Entry> {
+    pub fn read_dir(
+        src: &PathBuf,
+        dir: &PathBuf,
+        ignore_stack: &IgnoreStack,
+        state: Arc<State>,
+    ) -> Vec<Entry> {
         let mut entries = vec![];
         match fs::read_dir(dir) {
             Ok(read_dir) => {
                 for entry_result in read_dir.into_iter() {
                     match entry_result {
-                        Ok(entry) => match entry.file_type() {
-                            Ok(file_type) => {
-                                if file_type.is_dir() {
-                                    entries.push(Entry::Dir(src.to_path_buf(), entry.path()))
-                                } else if file_type.is_file() {
+                        // symlink_metadata doesn't follow the link, so a symlink is seen as one.
+                        Ok(entry) => match fs::symlink_metadata(entry.path()) {
+                            Ok(metadata) => {
+                                let is_dir = metadata.is_dir();
+                                if is_excluded(src, &entry.path(), is_dir, ignore_stack, &state) {
+                                    continue;
+                                }
+                                if metadata.file_type().is_symlink() {
+                                    if state.archive || state.tar_output {
+                                        entries
+                                            .push(Entry::Symlink(src.to_path_buf(), entry.path()))
+                                    }
+                                } else if is_dir {
+                                    entries.push(Entry::Dir(
+                                        src.to_path_buf(),
+                                        entry.path(),
+                                        ignore_stack.clone(),
+                                    ))
+                                } else if metadata.is_file() {
                                     entries.push(Entry::File(src.to_path_buf(), entry.path()))
                                 }
                             }
@@ -117,31 +672,191 @@ mod lib {
         return entries;
     }
 
-    fn get_dest(src: &PathBuf, dest: &PathBuf, file: &PathBuf) -> PathBuf {
+    fn is_excluded(
+        src: &PathBuf,
+        path: &PathBuf,
+        is_dir: bool,
+        ignore_stack: &IgnoreStack,
+        state: &Arc<State>,
+    ) -> bool {
+        let relative = match path.strip_prefix(src) {
+            Ok(relative) => relative,
+            Err(_) => return false,
+        };
+        if let Some(pattern_rules) = &state.pattern_rules {
+            if pattern_rules.is_excluded(relative) {
+                return true;
+            }
+        }
+        // Each layered Gitignore strips its own root internally, so it needs
+        // the real path, not one made relative to a different, shallower root.
+        state.respect_gitignore && ignore_stack.is_ignored(path, is_dir)
+    }
+
+    // No collision tracking: directories legitimately merge across sources,
+    // so only file/symlink destinations go through `get_dest`'s check.
+    fn get_dir_dest(src: &PathBuf, dest: &PathBuf, dir: &PathBuf, state: &Arc<State>) -> PathBuf {
+        let relative = dir.strip_prefix(src).expect("Not a prefix");
+        if state.rename_rules.is_empty() {
+            dest.join(relative)
+        } else {
+            dest.join(rename::apply(&state.rename_rules, relative))
+        }
+    }
+
+    // Returns None (after reporting the collision) when --rename made two
+    // different sources land on the same destination.
+    fn get_dest(src: &PathBuf, dest: &PathBuf, file: &PathBuf, state: &Arc<State>) -> Option<PathBuf> {
         let relative = file.strip_prefix(src).expect("Not a prefix");
-        dest.join(relative)
+        if state.rename_rules.is_empty() {
+            return Some(dest.join(relative));
+        }
+
+        let renamed = rename::apply(&state.rename_rules, relative);
+        let new_dest = dest.join(&renamed);
+
+        let mut claims = state.dest_claims.lock().unwrap();
+        if let Some(existing_src) = claims.get(&new_dest) {
+            if existing_src != file {
+                send_to_error(
+                    state.clone(),
+                    format!(
+                        "--rename collision: '{}' and '{}' both map to '{}'",
+                        existing_src.display(),
+                        file.display(),
+                        new_dest.display()
+                    ),
+                );
+                return None;
+            }
+        }
+        claims.insert(new_dest.clone(), file.to_path_buf());
+        Some(new_dest)
     }
 
     pub fn mk_dir(src: &PathBuf, dest: &PathBuf, dir: &PathBuf, state: Arc<State>) {
-        let new_dest = get_dest(src, dest, dir);
-        if let Err(error) = fs::create_dir_all(new_dest) {
+        let new_dest = get_dir_dest(src, dest, dir, &state);
+        if let Err(error) = fs::create_dir_all(&new_dest) {
             send_to_error(state.clone(), error.to_string())
         }
+        #[cfg(unix)]
+        if state.archive {
+            archive::preserve_metadata(dir, &new_dest, state.clone());
+        }
+    }
+
+    fn hash_file(path: &PathBuf) -> std::io::Result<[u8; 32]> {
+        let mut file = fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher)?;
+        Ok(hasher.finalize().into())
+    }
+
+    fn already_up_to_date(state: &Arc<State>, file: &PathBuf, new_dest: &PathBuf) -> bool {
+        let src_metadata = match fs::metadata(file) {
+            Ok(metadata) => metadata,
+            Err(_) => return false,
+        };
+        let dest_metadata = match fs::metadata(new_dest) {
+            Ok(metadata) => metadata,
+            Err(_) => return false, // destination doesn't exist yet
+        };
+
+        if state.checksum {
+            return match (hash_file(file), hash_file(new_dest)) {
+                (Ok(a), Ok(b)) => a == b,
+                _ => false,
+            };
+        }
+
+        src_metadata.len() == dest_metadata.len()
+            && src_metadata.modified().ok() == dest_metadata.modified().ok()
+    }
+
+    fn record_skip(state: &Arc<State>, file: &PathBuf) {
+        state.files_skipped.fetch_add(1, Ordering::SeqCst);
+        if let Ok(metadata) = fs::metadata(file) {
+            state.bytes_saved.fetch_add(metadata.len(), Ordering::SeqCst);
+        }
     }
 
     pub fn cp_file(src: &PathBuf, dest: &PathBuf, file: &PathBuf, state: Arc<State>) {
-        let new_dest = get_dest(src, dest, file);
-        if let Err(error) = fs::copy(file, new_dest) {
+        let new_dest = match get_dest(src, dest, file, &state) {
+            Some(new_dest) => new_dest,
+            None => return,
+        };
+
+        if state.update && already_up_to_date(&state, file, &new_dest) {
+            record_skip(&state, file);
+            return;
+        }
+
+        let hash = match hash_file(file) {
+            Ok(hash) => Some(hash),
+            Err(error) => {
+                send_to_error(state.clone(), error.to_string());
+                None
+            }
+        };
+
+        if let Some(hash) = hash {
+            let already_materialized = { state.hashes.lock().unwrap().get(&hash).cloned() };
+            if let Some(existing_dest) = already_materialized {
+                match fs::hard_link(&existing_dest, &new_dest) {
+                    Ok(()) => {
+                        record_skip(&state, file);
+                        return;
+                    }
+                    Err(error) => send_to_error(state.clone(), error.to_string()),
+                }
+            }
+        }
+
+        if let Err(error) = fs::copy(file, &new_dest) {
             send_to_error(state.clone(), error.to_string())
+        } else {
+            if let Some(hash) = hash {
+                state
+                    .hashes
+                    .lock()
+                    .unwrap()
+                    .entry(hash)
+                    .or_insert_with(|| new_dest.clone());
+            }
+            // --archive already preserves mtime below; without it, --update still
+            // needs one to compare against on the next run.
+            #[cfg(unix)]
+            if state.update && !state.archive {
+                archive::copy_mtime(file, &new_dest, state.clone());
+            }
+        }
+        #[cfg(unix)]
+        if state.archive {
+            archive::preserve_metadata(file, &new_dest, state.clone());
         }
     }
 
+    // Only reached on the non-tar path, since tar packing sends
+    // `Entry::Symlink` values to the archive writer instead.
+    #[cfg(unix)]
+    pub fn cp_symlink(src: &PathBuf, dest: &PathBuf, link: &PathBuf, state: Arc<State>) {
+        let new_dest = match get_dest(src, dest, link, &state) {
+            Some(new_dest) => new_dest,
+            None => return,
+        };
+        archive::recreate_symlink(link, &new_dest, state.clone());
+        archive::preserve_metadata(link, &new_dest, state.clone());
+    }
+
     pub fn update_task(id: &u16, task: Task, padding: &u16, state: Arc<State>) {
         let text: String;
         match &task {
             Task::Coping(file) => {
                 text = format!("Copying {}", file.display());
             }
+            Task::Archiving(file) => {
+                text = format!("Archiving {}", file.display());
+            }
             Task::Idle => {
                 text = format!("Idle");
             }
@@ -174,7 +889,9 @@ mod lib {
 
     pub fn update_totals(state: Arc<State>) {
         let entries_processed = { state.entries_processed.lock().unwrap() };
-        let entry_count = { state.entries.lock().unwrap().len() as u64 };
+        let entry_count = state.pending_entries.load(Ordering::SeqCst) as u64;
+        let files_skipped = state.files_skipped.load(Ordering::SeqCst);
+        let bytes_saved = state.bytes_saved.load(Ordering::SeqCst);
         let mut stdout = state.stdout.lock().unwrap();
         execute!(
             stdout,
@@ -183,13 +900,180 @@ mod lib {
             style::Print(format!("Entries processed: {}", entries_processed)),
             cursor::MoveTo(0, 1),
             terminal::Clear(terminal::ClearType::UntilNewLine),
-            style::Print(format!("Entries remaining: {}", entry_count))
+            style::Print(format!("Entries remaining: {}", entry_count)),
+            cursor::MoveTo(0, 3),
+            terminal::Clear(terminal::ClearType::UntilNewLine),
+            style::Print(format!("Files skipped: {}", files_skipped)),
+            cursor::MoveTo(0, 4),
+            terminal::Clear(terminal::ClearType::UntilNewLine),
+            style::Print(format!("Bytes saved: {}", bytes_saved))
         )
         .unwrap();
         stdout.flush().unwrap();
     }
 
-    pub fn main(sources: Vec<PathBuf>, dest: PathBuf) {
+    // Mirrors the `find_task` pattern crossbeam-deque's own docs (and
+    // ripgrep) use: drain the worker's own queue first, then fall back to
+    // stealing a batch from the injector, then finally try every sibling's
+    // deque before giving up for this round.
+    fn find_task<T>(local: &Deque<T>, global: &Injector<T>, stealers: &[Stealer<T>]) -> Option<T> {
+        local.pop().or_else(|| {
+            std::iter::repeat_with(|| {
+                global
+                    .steal_batch_and_pop(local)
+                    .or_else(|| stealers.iter().map(|s| s.steal()).collect())
+            })
+            .find(|s| !s.is_retry())
+            .and_then(|s| s.success())
+        })
+    }
+
+    fn process_entry(
+        entry: Entry,
+        id: u16,
+        dest: &PathBuf,
+        tar_tx: &Option<mpsc::Sender<TarJob>>,
+        local: &Deque<Entry>,
+        stealers: &[Stealer<Entry>],
+        state: &Arc<State>,
+    ) {
+        const PADDING: u16 = 5;
+        match entry {
+            Entry::Dir(src, dir, ignore_stack) => {
+                update_task(&id, Task::Scanning(dir.to_path_buf()), &PADDING, state.clone());
+                match tar_tx {
+                    Some(tx) => {
+                        let name = get_dir_dest(&src, &PathBuf::new(), &dir, state);
+                        let _ = tx.send(TarJob::Dir {
+                            disk_path: dir.to_path_buf(),
+                            name,
+                        });
+                    }
+                    None => mk_dir(&src, dest, &dir, state.clone()),
+                }
+                let ignore_stack = if state.respect_gitignore {
+                    ignore_stack.push(&dir)
+                } else {
+                    ignore_stack
+                };
+                // A worker waiting here keeps draining other queued entries itself
+                // instead of spinning idle: with -j 1 (or every worker landing on a
+                // Dir at once) it's the only thing that can bring pending_entries
+                // back down, so blocking without making progress would deadlock.
+                if let Some(limit) = state.max_queued {
+                    while state.pending_entries.load(Ordering::SeqCst) >= limit {
+                        match find_task(local, &state.injector, stealers) {
+                            Some(next) => {
+                                state.pending_entries.fetch_sub(1, Ordering::SeqCst);
+                                process_entry(next, id, dest, tar_tx, local, stealers, state);
+                            }
+                            None => thread::yield_now(),
+                        }
+                    }
+                }
+                let new_entries = read_dir(&src, &dir, &ignore_stack, state.clone());
+                state
+                    .pending_entries
+                    .fetch_add(new_entries.len(), Ordering::SeqCst);
+                for new_entry in new_entries {
+                    local.push(new_entry);
+                }
+                {
+                    let mut dirs_processed = state.entries_processed.lock().unwrap();
+                    *dirs_processed += 1;
+                };
+                update_totals(state.clone())
+            }
+            Entry::File(src, file) => {
+                update_task(
+                    &id,
+                    if tar_tx.is_some() {
+                        Task::Archiving(file.to_path_buf())
+                    } else {
+                        Task::Coping(file.to_path_buf())
+                    },
+                    &PADDING,
+                    state.clone(),
+                );
+                match tar_tx {
+                    Some(tx) => {
+                        if let Some(name) = get_dest(&src, &PathBuf::new(), &file, state) {
+                            let _ = tx.send(TarJob::File {
+                                disk_path: file.to_path_buf(),
+                                name,
+                            });
+                        }
+                    }
+                    None => cp_file(&src, dest, &file, state.clone()),
+                }
+                {
+                    let mut files_processed = state.entries_processed.lock().unwrap();
+                    *files_processed += 1;
+                };
+                update_totals(state.clone())
+            }
+            Entry::Symlink(src, link) => {
+                update_task(
+                    &id,
+                    if tar_tx.is_some() {
+                        Task::Archiving(link.to_path_buf())
+                    } else {
+                        Task::Coping(link.to_path_buf())
+                    },
+                    &PADDING,
+                    state.clone(),
+                );
+                match tar_tx {
+                    // Packing a symlink into a tar doesn't need unix-only metadata calls.
+                    Some(tx) => {
+                        if let Some(name) = get_dest(&src, &PathBuf::new(), &link, state) {
+                            let _ = tx.send(TarJob::Symlink {
+                                disk_path: link.to_path_buf(),
+                                name,
+                            });
+                        }
+                    }
+                    #[cfg(unix)]
+                    None => cp_symlink(&src, dest, &link, state.clone()),
+                    #[cfg(not(unix))]
+                    None => send_to_error(
+                        state.clone(),
+                        format!(
+                            "cannot copy symlink '{}': --archive symlink support requires unix",
+                            link.display()
+                        ),
+                    ),
+                }
+                {
+                    let mut files_processed = state.entries_processed.lock().unwrap();
+                    *files_processed += 1;
+                };
+                update_totals(state.clone())
+            }
+        }
+    }
+
+    pub fn main(sources: Vec<PathBuf>, dest: PathBuf, options: Options) {
+        let Options {
+            archive,
+            pattern_rule_specs,
+            respect_gitignore,
+            update,
+            checksum,
+            archive_format,
+            rename_specs,
+            use_regex,
+            threads,
+            max_queued,
+        } = options;
+
+        let archive_format = ArchiveFormat::detect(&dest, &archive_format);
+
+        let rename_rules: Vec<RenameRule> = rename_specs
+            .iter()
+            .map(|spec| RenameRule::parse(spec, use_regex).expect("invalid --rename pattern"))
+            .collect();
+
         let formatter = Formatter3164 {
             facility: Facility::LOG_USER,
             hostname: None,
@@ -202,43 +1086,82 @@ mod lib {
             .map(|()| log::set_max_level(LevelFilter::Info))
             .unwrap();
 
-        if sources.len() > 1 {
+        if archive_format.is_none() && sources.len() > 1 {
             if dest.is_file() {
                 panic!("If there are multiple sources, the desination must be a directory.");
             }
         }
 
-        let mut entries: Vec<Entry> = vec![];
+        let pattern_rules = PatternRules::build(&pattern_rule_specs)
+            .expect("could not compile --exclude/--include glob");
+
+        let injector = Injector::new();
+        let mut seed_count = 0usize;
 
         for entry in &sources {
-            if entry.is_dir() {
+            let entry = if entry.is_dir() {
                 let path_str = entry.to_str().expect("Could not get path_str");
                 if path_str.ends_with("/") {
-                    entries.push(Entry::Dir(entry.to_path_buf(), entry.to_path_buf()));
+                    Entry::Dir(entry.to_path_buf(), entry.to_path_buf(), IgnoreStack::root())
                 } else {
-                    entries.push(Entry::Dir(entry.parent().unwrap().to_path_buf(), entry.to_path_buf()));
+                    Entry::Dir(
+                        entry.parent().unwrap().to_path_buf(),
+                        entry.to_path_buf(),
+                        IgnoreStack::root(),
+                    )
                 }
             } else if entry.is_file() {
-                entries.push(Entry::File(entry.parent().unwrap().to_path_buf(), entry.to_path_buf()));
+                Entry::File(entry.parent().unwrap().to_path_buf(), entry.to_path_buf())
             } else {
                 panic!("Entry found is neither a file or directory");
-            }
+            };
+            injector.push(entry);
+            seed_count += 1;
         }
 
         let main_state = Arc::new(State {
             sources: Mutex::new(sources),
             dest: Mutex::new(dest.to_path_buf()),
-            entries: Mutex::new(entries),
+            injector,
+            pending_entries: AtomicUsize::new(seed_count),
+            active_workers: AtomicUsize::new(0),
             next_id: Mutex::new(0),
             workers: Mutex::new(HashMap::new()),
             stdout: Mutex::new(stdout()),
             entries_processed: Mutex::new(0),
+            archive,
+            tar_output: archive_format.is_some(),
+            pattern_rules,
+            respect_gitignore,
+            hashes: Mutex::new(HashMap::new()),
+            update,
+            checksum,
+            bytes_saved: AtomicU64::new(0),
+            files_skipped: AtomicU64::new(0),
+            rename_rules,
+            dest_claims: Mutex::new(HashMap::new()),
+            max_queued,
         });
 
-        const PADDING: u16 = 3;
-        let cpu_count = num_cpus::get() as u64;
+        let archive_writer = archive_format.map(|format| {
+            let (tx, rx) = mpsc::channel::<TarJob>();
+            let writer_state = main_state.clone();
+            let archive_dest = dest.to_path_buf();
+            let handle = thread::spawn(move || tar_archive::run(&archive_dest, format, rx, writer_state));
+            (tx, handle)
+        });
+
+        const PADDING: u16 = 5;
+        let cpu_count = match threads {
+            Some(0) => panic!("--threads must be at least 1"),
+            Some(threads) => threads as u64,
+            None => num_cpus::get() as u64,
+        };
+        let threads_line = match max_queued {
+            Some(limit) => format!("Threads: {} (max-queued: {})", cpu_count, limit),
+            None => format!("Threads: {}", cpu_count),
+        };
 
-        let entry_count = { main_state.entries.lock().unwrap().len() as u64 };
         {
             let mut stdout = main_state.stdout.lock().unwrap();
             execute!(
@@ -246,20 +1169,30 @@ mod lib {
                 terminal::EnterAlternateScreen,
                 terminal::Clear(terminal::ClearType::All),
                 cursor::MoveTo(0, 0),
-                style::Print(format!("Entries remaining: {}", entry_count)),
+                style::Print(format!("Entries remaining: {}", seed_count)),
                 cursor::MoveTo(0, 1),
                 style::Print(format!("Entries processed: {}", 0)),
                 cursor::MoveTo(0, 2),
-                style::Print(format!("Threads: {}", cpu_count))
+                style::Print(threads_line),
+                cursor::MoveTo(0, 3),
+                style::Print(format!("Files skipped: {}", 0)),
+                cursor::MoveTo(0, 4),
+                style::Print(format!("Bytes saved: {}", 0))
             )
             .unwrap();
             stdout.flush().unwrap();
         }
 
-        let handles = (0..cpu_count)
+        // Every worker gets its own double-ended deque; siblings can steal
+        // from the far end while the owner pushes/pops from its own end.
+        let locals: Vec<Deque<Entry>> = (0..cpu_count).map(|_| Deque::new_lifo()).collect();
+        let stealers: Vec<Stealer<Entry>> = locals.iter().map(|local| local.stealer()).collect();
+
+        let handles = locals
             .into_iter()
-            .map(|_| {
+            .map(|local| {
                 let state = main_state.clone();
+                let stealers = stealers.clone();
                 let id = {
                     let mut next_id = state.next_id.lock().unwrap();
                     let id = *next_id;
@@ -275,62 +1208,25 @@ mod lib {
                 }
                 update_task(&id, Task::Initalizing, &PADDING, state.clone());
                 let dest = { state.dest.lock().unwrap().to_path_buf() };
-                thread::spawn(move || {
-                    loop {
-                        let entry_options = { state.entries.lock().unwrap().pop() };
-                        match entry_options {
-                            Some(entry) => match entry {
-                                Entry::Dir(src, dir) => {
-                                    update_task(
-                                        &id,
-                                        Task::Scanning(dir.to_path_buf()),
-                                        &PADDING,
-                                        state.clone(),
-                                    );
-                                    // thread::sleep(Duration::from_secs(2));
-                                    mk_dir(&src, &dest, &dir, state.clone());
-                                    let mut new_entries = read_dir(&src, &dir, state.clone());
-                                    {
-                                        let mut entries = state.entries.lock().unwrap();
-                                        entries.append(&mut new_entries);
-                                    }
-                                    {
-                                        let mut dirs_processed =
-                                            state.entries_processed.lock().unwrap();
-                                        *dirs_processed += 1;
-                                    };
-                                    update_totals(state.clone())
-                                }
-                                Entry::File(src, file) => {
-                                    update_task(
-                                        &id,
-                                        Task::Coping(file.to_path_buf()),
-                                        &PADDING,
-                                        state.clone(),
-                                    );
-                                    // thread::sleep(Duration::from_secs(2));
-                                    cp_file(&src, &dest, &file, state.clone());
-                                    {
-                                        let mut files_processed =
-                                            state.entries_processed.lock().unwrap();
-                                        *files_processed += 1;
-                                    };
-                                    update_totals(state.clone())
-                                }
-                            },
-                            None => {}
+                let tar_tx = archive_writer.as_ref().map(|(tx, _)| tx.clone());
+                thread::spawn(move || loop {
+                    match find_task(&local, &state.injector, &stealers) {
+                        Some(entry) => {
+                            state.active_workers.fetch_add(1, Ordering::SeqCst);
+                            // Decremented here, not on completion, so --max-queued below
+                            // measures only still-queued work, not what this worker holds.
+                            state.pending_entries.fetch_sub(1, Ordering::SeqCst);
+                            process_entry(entry, id, &dest, &tar_tx, &local, &stealers, &state);
+                            state.active_workers.fetch_sub(1, Ordering::SeqCst);
                         }
-                        update_task(&id, Task::Idle, &PADDING, state.clone());
-                        let workers = state.workers.lock().unwrap();
-                        let mut should_break = true;
-                        for (_id, worker) in workers.iter() {
-                            match worker.task {
-                                Task::Idle => {}
-                                _ => should_break = false,
+                        None => {
+                            update_task(&id, Task::Idle, &PADDING, state.clone());
+                            if state.pending_entries.load(Ordering::SeqCst) == 0
+                                && state.active_workers.load(Ordering::SeqCst) == 0
+                            {
+                                break;
                             }
-                        }
-                        if should_break {
-                            break;
+                            thread::yield_now();
                         }
                     }
                 })
@@ -341,6 +1237,12 @@ mod lib {
             thread.join().unwrap();
         }
 
+        // Drops the writer's own sender so its channel closes once workers finish.
+        if let Some((tx, handle)) = archive_writer {
+            drop(tx);
+            handle.join().unwrap();
+        }
+
         {
             let mut stdout = main_state.stdout.lock().unwrap();
             execute!(stdout, terminal::LeaveAlternateScreen).unwrap();